@@ -1,22 +1,27 @@
 extern crate image;
-extern crate rand;
 extern crate time;
 
 use std::f32;
 mod math;
+mod scenes;
 use math::cross;
 use math::dot;
 use math::point_at_ray;
 use math::random_in_unit_disk;
 use math::random_in_unit_sphere;
+use math::surrounding_box;
+use math::Aabb;
+use math::Pcg32;
 use math::Ray;
 use math::Vector3;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 use time::PreciseTime;
 
 const KMIN_T: f32 = 0.001;
 const KMAX_T: f32 = 10000000.0;
+const TILE_SIZE: u32 = 32;
 
 fn linear_to_srgb(val: f32) -> u8 {
     let mut new = val.max(0.0);
@@ -25,6 +30,44 @@ fn linear_to_srgb(val: f32) -> u8 {
     return new_u8.min(255);
 }
 
+/// Derives a per-pixel seed from a base seed and pixel coordinates so each
+/// pixel's sample sequence is reproducible independent of thread scheduling.
+fn pixel_seed(base_seed: u64, x: u32, y: u32) -> u64 {
+    base_seed
+        .wrapping_add((x as u64).wrapping_mul(0x9E3779B97F4A7C15))
+        .wrapping_add((y as u64).wrapping_mul(0xC2B2AE3D27D4EB4F))
+}
+
+/// Maps a flat tile index from the work queue back to the pixel rectangle
+/// `[x0, x1) x [y0, y1)` it covers, clamped to the image bounds.
+fn tile_bounds(tile_index: usize, tiles_x: u32, width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let tile_x = (tile_index as u32) % tiles_x;
+    let tile_y = (tile_index as u32) / tiles_x;
+    let x0 = tile_x * TILE_SIZE;
+    let y0 = tile_y * TILE_SIZE;
+    let x1 = (x0 + TILE_SIZE).min(width);
+    let y1 = (y0 + TILE_SIZE).min(height);
+    return (x0, y0, x1, y1);
+}
+
+/// Picks the worker pool size: the first CLI argument if given, otherwise
+/// the machine's available parallelism, falling back to 4.
+fn worker_thread_count() -> usize {
+    let from_arg = std::env::args().nth(1).and_then(|arg| arg.parse().ok());
+    return from_arg.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4)
+    });
+}
+
+/// Picks the RNG base seed: the second CLI argument if given, otherwise a
+/// fixed default so runs stay reproducible unless a seed is asked for.
+fn base_seed() -> u64 {
+    let from_arg = std::env::args().nth(2).and_then(|arg| arg.parse().ok());
+    return from_arg.unwrap_or(0xC0FFEE);
+}
+
 #[derive(Debug)]
 struct Scatter {
     scattered: Ray,
@@ -32,7 +75,7 @@ struct Scatter {
 }
 
 trait Material {
-    fn scatter(&self, ray_in: &Ray, hit: &Hit) -> Option<Scatter>;
+    fn scatter(&self, ray_in: &Ray, hit: &Hit, rng: &mut Pcg32) -> Option<Scatter>;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -43,14 +86,16 @@ struct Lambertian {
 #[derive(Debug, Copy, Clone)]
 struct Metal {
     albedo: Vector3,
+    fuzz: f32,
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, ray_in: &Ray, hit: &Hit) -> Option<Scatter> {
-        let target = hit.position + hit.normal + random_in_unit_sphere();
+    fn scatter(&self, ray_in: &Ray, hit: &Hit, rng: &mut Pcg32) -> Option<Scatter> {
+        let target = hit.position + hit.normal + random_in_unit_sphere(rng);
         let scattered = Ray {
             origin: hit.position + 0.001,
             direction: target - hit.position,
+            time: ray_in.time,
         };
         return Some(Scatter {
             attenuation: self.albedo,
@@ -64,11 +109,12 @@ fn reflect(v: &Vector3, n: &Vector3) -> Vector3 {
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray_in: &Ray, hit: &Hit) -> Option<Scatter> {
+    fn scatter(&self, ray_in: &Ray, hit: &Hit, rng: &mut Pcg32) -> Option<Scatter> {
         let reflected = reflect(&ray_in.direction.normalize(), &hit.normal);
         let scattered = Ray {
             origin: hit.position,
-            direction: reflected,
+            direction: reflected + random_in_unit_sphere(rng) * self.fuzz,
+            time: ray_in.time,
         };
         if dot(&scattered.direction, &hit.normal) > 0.0 {
             return Some(Scatter {
@@ -81,6 +127,67 @@ impl Material for Metal {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+struct Dielectric {
+    ref_idx: f32,
+}
+
+fn refract(v: &Vector3, n: &Vector3, ni_over_nt: f32) -> Option<Vector3> {
+    let dt = dot(v, n);
+    let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
+    if discriminant > 0.0 {
+        return Some((*v - *n * dt) * ni_over_nt - *n * discriminant.sqrt());
+    }
+    return None;
+}
+
+fn schlick(cosine: f32, ref_idx: f32) -> f32 {
+    let r0 = ((1.0 - ref_idx) / (1.0 + ref_idx)).powi(2);
+    return r0 + (1.0 - r0) * (1.0 - cosine).powi(5);
+}
+
+impl Material for Dielectric {
+    fn scatter(&self, ray_in: &Ray, hit: &Hit, rng: &mut Pcg32) -> Option<Scatter> {
+        let d_unit = ray_in.direction.normalize();
+        let reflected = reflect(&d_unit, &hit.normal);
+        let attenuation = Vector3::new(1.0, 1.0, 1.0);
+
+        let (outward_normal, ni_over_nt, cosine) = if dot(&d_unit, &hit.normal) > 0.0 {
+            (
+                hit.normal * -1.0,
+                self.ref_idx,
+                self.ref_idx * dot(&d_unit, &hit.normal),
+            )
+        } else {
+            (
+                hit.normal,
+                1.0 / self.ref_idx,
+                -dot(&d_unit, &hit.normal),
+            )
+        };
+
+        let direction = match refract(&d_unit, &outward_normal, ni_over_nt) {
+            Some(refracted) => {
+                if rng.next_f32() < schlick(cosine, self.ref_idx) {
+                    reflected
+                } else {
+                    refracted
+                }
+            }
+            None => reflected,
+        };
+
+        return Some(Scatter {
+            attenuation: attenuation,
+            scattered: Ray {
+                origin: hit.position,
+                direction: direction,
+                time: ray_in.time,
+            },
+        });
+    }
+}
+
 struct Hit<'a> {
     position: Vector3,
     normal: Vector3,
@@ -88,6 +195,11 @@ struct Hit<'a> {
     material: &'a Box<Material + Send + Sync + 'a>,
 }
 
+trait Hittable: Send + Sync {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Hit>;
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb>;
+}
+
 struct Sphere {
     radius: f32,
     position: Vector3,
@@ -104,6 +216,8 @@ struct Camera {
     v: Vector3,
     w: Vector3,
     lens_radius: f32,
+    time0: f32,
+    time1: f32,
 }
 
 impl Camera {
@@ -115,6 +229,8 @@ impl Camera {
         aspect: f32,
         aperture: f32,
         focus_dist: f32,
+        time0: f32,
+        time1: f32,
     ) -> Camera {
         let lens_radius = aperture / 2.0;
         let theta = vfov * f32::consts::PI / 180.0;
@@ -137,11 +253,13 @@ impl Camera {
                 - (w * focus_dist),
             horizontal: u * (2.0 * half_width * focus_dist),
             vertical: v * (2.0 * half_height * focus_dist),
+            time0: time0,
+            time1: time1,
         };
     }
 
-    fn make_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = random_in_unit_disk() * self.lens_radius;
+    fn make_ray(&self, s: f32, t: f32, rng: &mut Pcg32) -> Ray {
+        let rd = random_in_unit_disk(rng) * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
         let direction = self.lower_left_corner + (self.horizontal * s) + (self.vertical * t)
             - self.origin
@@ -149,87 +267,223 @@ impl Camera {
         return Ray {
             origin: self.origin + offset,
             direction: direction.normalize(),
+            time: self.time0 + rng.next_f32() * (self.time1 - self.time0),
         };
     }
 }
 
-fn ray_sphere_intersection<'a>(
-    ray: &Ray,
-    sphere: &'a Sphere,
-    t_min: f32,
-    t_max: f32,
-) -> Option<Hit<'a>> {
-    let oc = ray.origin - sphere.position;
-    let a = dot(&ray.direction, &ray.direction);
-    let b = dot(&oc, &ray.direction);
-    let c = dot(&oc, &oc) - sphere.radius * sphere.radius;
-    let discriminant = b * b - a * c;
+impl Hittable for Sphere {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        let oc = ray.origin - self.position;
+        let a = dot(&ray.direction, &ray.direction);
+        let b = dot(&oc, &ray.direction);
+        let c = dot(&oc, &oc) - self.radius * self.radius;
+        let discriminant = b * b - a * c;
 
-    if discriminant > 0.0 {
-        let discriminant_sq = discriminant.sqrt();
-        let mut t = (-b - discriminant_sq) / a;
-        if t < t_max && t > t_min {
-            let hit_position = point_at_ray(&ray, t);
-            let hit_normal = (hit_position - sphere.position) * (1.0 / sphere.radius);
-            return Some(Hit {
-                position: hit_position,
-                normal: hit_normal.normalize(),
-                t: t,
-                material: &sphere.material,
-            });
-        }
+        if discriminant > 0.0 {
+            let discriminant_sq = discriminant.sqrt();
+            let mut t = (-b - discriminant_sq) / a;
+            if t < t_max && t > t_min {
+                let hit_position = point_at_ray(&ray, t);
+                let hit_normal = (hit_position - self.position) * (1.0 / self.radius);
+                return Some(Hit {
+                    position: hit_position,
+                    normal: hit_normal.normalize(),
+                    t: t,
+                    material: &self.material,
+                });
+            }
 
-        t = (-b + discriminant_sq) / a;
-        if t < t_max && t > t_min {
-            let hit_position = point_at_ray(&ray, t);
-            let hit_normal = (hit_position - sphere.position) * (1.0 / sphere.radius);
-            return Some(Hit {
-                position: hit_position,
-                normal: hit_normal,
-                t: t,
-                material: &sphere.material,
-            });
+            t = (-b + discriminant_sq) / a;
+            if t < t_max && t > t_min {
+                let hit_position = point_at_ray(&ray, t);
+                let hit_normal = (hit_position - self.position) * (1.0 / self.radius);
+                return Some(Hit {
+                    position: hit_position,
+                    normal: hit_normal,
+                    t: t,
+                    material: &self.material,
+                });
+            }
         }
+        return None;
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        let radius = Vector3::new(self.radius, self.radius, self.radius);
+        return Some(Aabb {
+            min: self.position - radius,
+            max: self.position + radius,
+        });
     }
-    return None;
 }
 
-fn intersect_scene<'a>(
-    ray: &Ray,
-    spheres: &'a [Sphere],
-    t_min: f32,
-    t_max: f32,
-) -> Option<Hit<'a>> {
-    let mut closest_t = t_max;
-    let mut closest_hit = None;
-    for i in 0..spheres.len() {
-        let result = ray_sphere_intersection(&ray, &spheres[i], t_min, closest_t);
-        closest_hit = match result {
-            Some(hit) => {
-                if hit.t > KMIN_T && hit.t < closest_t {
-                    closest_t = hit.t;
-                    Some(hit)
-                } else {
-                    closest_hit
-                }
+struct MovingSphere {
+    radius: f32,
+    center0: Vector3,
+    center1: Vector3,
+    time0: f32,
+    time1: f32,
+    material: Box<Material + Send + Sync>,
+}
+
+impl MovingSphere {
+    fn center(&self, time: f32) -> Vector3 {
+        let fraction = (time - self.time0) / (self.time1 - self.time0);
+        return self.center0 + (self.center1 - self.center0) * fraction;
+    }
+}
+
+impl Hittable for MovingSphere {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        let center = self.center(ray.time);
+        let oc = ray.origin - center;
+        let a = dot(&ray.direction, &ray.direction);
+        let b = dot(&oc, &ray.direction);
+        let c = dot(&oc, &oc) - self.radius * self.radius;
+        let discriminant = b * b - a * c;
+
+        if discriminant > 0.0 {
+            let discriminant_sq = discriminant.sqrt();
+            let mut t = (-b - discriminant_sq) / a;
+            if t < t_max && t > t_min {
+                let hit_position = point_at_ray(&ray, t);
+                let hit_normal = (hit_position - center) * (1.0 / self.radius);
+                return Some(Hit {
+                    position: hit_position,
+                    normal: hit_normal.normalize(),
+                    t: t,
+                    material: &self.material,
+                });
+            }
+
+            t = (-b + discriminant_sq) / a;
+            if t < t_max && t > t_min {
+                let hit_position = point_at_ray(&ray, t);
+                let hit_normal = (hit_position - center) * (1.0 / self.radius);
+                return Some(Hit {
+                    position: hit_position,
+                    normal: hit_normal,
+                    t: t,
+                    material: &self.material,
+                });
             }
-            None => closest_hit,
+        }
+        return None;
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
+        let radius = Vector3::new(self.radius, self.radius, self.radius);
+        let box0 = Aabb {
+            min: self.center(t0) - radius,
+            max: self.center(t0) + radius,
+        };
+        let box1 = Aabb {
+            min: self.center(t1) - radius,
+            max: self.center(t1) + radius,
+        };
+        return Some(surrounding_box(&box0, &box1));
+    }
+}
+
+struct BvhNode {
+    left: Box<Hittable>,
+    right: Box<Hittable>,
+    bbox: Aabb,
+}
+
+fn aabb_centroid(bbox: &Aabb, axis: usize) -> f32 {
+    match axis {
+        0 => 0.5 * (bbox.min.x + bbox.max.x),
+        1 => 0.5 * (bbox.min.y + bbox.max.y),
+        _ => 0.5 * (bbox.min.z + bbox.max.z),
+    }
+}
+
+impl BvhNode {
+    fn build(mut objects: Vec<Box<Hittable>>, t0: f32, t1: f32, depth: usize) -> Box<Hittable> {
+        if objects.is_empty() {
+            panic!("BvhNode::build requires at least one primitive");
+        }
+
+        let axis = depth % 3;
+        objects.sort_by(|a, b| {
+            let a_box = a
+                .bounding_box(t0, t1)
+                .expect("BvhNode requires bounded primitives");
+            let b_box = b
+                .bounding_box(t0, t1)
+                .expect("BvhNode requires bounded primitives");
+            aabb_centroid(&a_box, axis)
+                .partial_cmp(&aabb_centroid(&b_box, axis))
+                .unwrap()
+        });
+
+        if objects.len() == 1 {
+            return objects.pop().unwrap();
+        }
+
+        let (left, right) = if objects.len() == 2 {
+            let right = objects.pop().unwrap();
+            let left = objects.pop().unwrap();
+            (left, right)
+        } else {
+            let split = objects.split_off(objects.len() / 2);
+            let left = BvhNode::build(objects, t0, t1, depth + 1);
+            let right = BvhNode::build(split, t0, t1, depth + 1);
+            (left, right)
         };
+
+        let bbox = surrounding_box(
+            &left
+                .bounding_box(t0, t1)
+                .expect("BvhNode requires bounded primitives"),
+            &right
+                .bounding_box(t0, t1)
+                .expect("BvhNode requires bounded primitives"),
+        );
+        return Box::new(BvhNode {
+            left: left,
+            right: right,
+            bbox: bbox,
+        });
     }
-    closest_hit
 }
 
-fn trace(ray: &Ray, spheres: &[Sphere], depth: i32) -> Vector3 {
+impl Hittable for BvhNode {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        if !self.bbox.hit(ray, t_min, t_max) {
+            return None;
+        }
+        let left_hit = self.left.hit(ray, t_min, t_max);
+        let right_t_max = match &left_hit {
+            Some(hit) => hit.t,
+            None => t_max,
+        };
+        let right_hit = self.right.hit(ray, t_min, right_t_max);
+        match right_hit {
+            Some(hit) => Some(hit),
+            None => left_hit,
+        }
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        return Some(self.bbox);
+    }
+}
+
+fn trace(ray: &Ray, scene: &Hittable, depth: i32, rng: &mut Pcg32) -> Vector3 {
     if depth > 50 {
         return Vector3::origin();
     }
-    let result = intersect_scene(&ray, &spheres, KMIN_T, KMAX_T);
+    let result = scene.hit(&ray, KMIN_T, KMAX_T);
     match result {
         Some(hit) => {
-            let scatter = hit.material.scatter(&ray, &hit);
+            let scatter = hit.material.scatter(&ray, &hit, rng);
             match scatter {
                 Some(scattered) => {
-                    return trace(&scattered.scattered, &spheres, depth + 1) * scattered.attenuation;
+                    return trace(&scattered.scattered, scene, depth + 1, rng)
+                        * scattered.attenuation;
                 }
                 None => {
                     return Vector3::origin();
@@ -246,40 +500,14 @@ fn trace(ray: &Ray, spheres: &[Sphere], depth: i32) -> Vector3 {
 
 fn main() {
     let start = PreciseTime::now();
-    let sphere_1 = Sphere {
-        position: Vector3::new(0.5, 0.01, -1.0),
-        radius: 0.5,
-        material: Box::new(Lambertian {
-            albedo: Vector3::new(1.0, 0.1, 0.1),
-        }),
-    };
-    let sphere_2 = Sphere {
-        position: Vector3::new(0.5, -10000.5, -1.0),
-        radius: 10000.0,
-        material: Box::new(Lambertian {
-            albedo: Vector3::new(0.5, 0.5, 0.8),
-        }),
-    };
-    let sphere_3 = Sphere {
-        position: Vector3::new(-0.2, -0.295, -1.0),
-        radius: 0.2,
-        material: Box::new(Metal {
-            albedo: Vector3::new(0.5, 0.5, 0.5),
-        }),
-    };
-    let sphere_4 = Sphere {
-        position: Vector3::new(-0.8, 0.5, -3.0),
-        radius: 1.0,
-        material: Box::new(Metal {
-            albedo: Vector3::new(0.5, 0.5, 0.5),
-        }),
-    };
-    let spheres = Arc::new(vec![sphere_1, sphere_2, sphere_3, sphere_4]);
-
-    let look_from = Vector3::new(0.0, 0.0, 3.0);
-    let look_at = Vector3::new(0.0, 0.0, -1.0);
-    let dist_to_focus = 3.0;
-    let aperture = 0.00;
+    let base_seed = base_seed();
+    let objects = scenes::random_scene(base_seed);
+    let scene = Arc::new(BvhNode::build(objects, 0.0, 1.0, 0));
+
+    let look_from = Vector3::new(13.0, 2.0, 3.0);
+    let look_at = Vector3::new(0.0, 0.0, 0.0);
+    let dist_to_focus = 10.0;
+    let aperture = 0.1;
 
     let screen_height = 400;
     let screen_width = 600;
@@ -289,51 +517,73 @@ fn main() {
         look_from,
         look_at,
         Vector3::new(0.0, 1.0, 0.0),
-        30.0,
+        20.0,
         screen_width as f32 / screen_height as f32,
         aperture,
         dist_to_focus,
+        0.0,
+        1.0,
     );
     let mut handlers = vec![];
 
-    let imgbuf = Arc::new(Mutex::new(image::RgbImage::new(
-        screen_width,
-        screen_height,
-    )));
-    let threads = 4;
-    for t in 0..threads {
-        let mut i = t;
-        let local_scene = spheres.clone();
-        let imagebuf = Arc::clone(&imgbuf);
-        let handle = thread::spawn(move || {
-            while i < screen_height {
-                for j in 0..screen_width {
+    let tiles_x = (screen_width + TILE_SIZE - 1) / TILE_SIZE;
+    let tiles_y = (screen_height + TILE_SIZE - 1) / TILE_SIZE;
+    let tile_count = (tiles_x * tiles_y) as usize;
+    let next_tile = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    let threads = worker_thread_count();
+    for _ in 0..threads {
+        let local_scene = scene.clone();
+        let next_tile = Arc::clone(&next_tile);
+        let tx = tx.clone();
+        let handle = thread::spawn(move || loop {
+            let tile_index = next_tile.fetch_add(1, Ordering::SeqCst);
+            if tile_index >= tile_count {
+                break;
+            }
+            let (x0, y0, x1, y1) =
+                tile_bounds(tile_index, tiles_x, screen_width, screen_height);
+            let mut pixels = Vec::with_capacity(((x1 - x0) * (y1 - y0)) as usize);
+            for i in y0..y1 {
+                for j in x0..x1 {
+                    let mut rng = Pcg32::new(pixel_seed(base_seed, j, i), 0);
                     let mut color = Vector3::origin();
                     for _ in 0..spp {
-                        let u: f32 = (j as f32 + rand::random::<f32>()) / screen_width as f32;
-                        let v: f32 = (i as f32 + rand::random::<f32>()) / screen_height as f32;
-                        let ray = &camera.clone().make_ray(u, v);
-                        color = color + trace(&ray, &local_scene, 0);
+                        let u: f32 = (j as f32 + rng.next_f32()) / screen_width as f32;
+                        let v: f32 = (i as f32 + rng.next_f32()) / screen_height as f32;
+                        let ray = &camera.clone().make_ray(u, v, &mut rng);
+                        color = color + trace(&ray, &**local_scene, 0, &mut rng);
                     }
-                    let mut image = imagebuf.lock().unwrap();
-                    let pixel = image::Rgb([
+                    pixels.push(image::Rgb([
                         linear_to_srgb(color.x / spp as f32),
                         linear_to_srgb(color.y / spp as f32),
                         linear_to_srgb(color.z / spp as f32),
-                    ]);
-                    image.put_pixel(j, i, pixel);
+                    ]));
                 }
-                i += threads;
             }
+            tx.send((tile_index, pixels)).unwrap();
         });
         handlers.push(handle);
     }
+    drop(tx);
+
+    let mut imgbuf = image::RgbImage::new(screen_width, screen_height);
+    for (tile_index, pixels) in rx {
+        let (x0, y0, x1, y1) = tile_bounds(tile_index, tiles_x, screen_width, screen_height);
+        let mut k = 0;
+        for i in y0..y1 {
+            for j in x0..x1 {
+                imgbuf.put_pixel(j, i, pixels[k]);
+                k += 1;
+            }
+        }
+    }
 
     for handle in handlers {
         handle.join().unwrap();
     }
 
-    imgbuf.lock().unwrap().save("output.png").unwrap();
+    imgbuf.save("output.png").unwrap();
     let end = PreciseTime::now();
 
     println!(
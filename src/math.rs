@@ -1,5 +1,3 @@
-extern crate rand;
-
 use std::f32;
 use std::ops;
 
@@ -108,27 +106,65 @@ impl ops::Add<f32> for Vector3 {
 pub struct Ray {
     pub origin: Vector3,
     pub direction: Vector3,
+    pub time: f32,
 }
 
 pub fn dot(v1: &Vector3, v2: &Vector3) -> f32 {
     return v1.x * v2.x + v1.y * v2.y + v1.z * v2.z;
 }
 
-pub fn random_in_unit_sphere() -> Vector3 {
-    let mut p = Vector3::new(rand::random(), rand::random(), rand::random()) * 2.0
+/// A small PCG32 generator so renders are seedable and reproducible without
+/// relying on `rand`'s shared, thread-local global state.
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+
+impl Pcg32 {
+    pub fn new(seed: u64, seq: u64) -> Pcg32 {
+        let mut rng = Pcg32 {
+            state: 0,
+            inc: (seq << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        return rng;
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old_state = self.state;
+        self.state = old_state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(self.inc);
+        let xorshifted = (((old_state >> 18) ^ old_state) >> 27) as u32;
+        let rot = (old_state >> 59) as u32;
+        return (xorshifted >> rot) | (xorshifted << ((!rot).wrapping_add(1) & 31));
+    }
+
+    pub fn next_f32(&mut self) -> f32 {
+        // Keep the top 24 bits, which an f32 holds exactly, and divide by 2^24
+        // so the result is a half-open [0.0, 1.0) range; converting the full
+        // u32 to f32 would round the top values up to 1.0.
+        return ((self.next_u32() >> 8) as f32) / 16777216.0;
+    }
+}
+
+pub fn random_in_unit_sphere(rng: &mut Pcg32) -> Vector3 {
+    let mut p = Vector3::new(rng.next_f32(), rng.next_f32(), rng.next_f32()) * 2.0
         - Vector3::new(1.0, 1.0, 1.0);
     while dot(&p, &p) >= 1.0 {
-        p = Vector3::new(rand::random(), rand::random(), rand::random()) * 2.0
+        p = Vector3::new(rng.next_f32(), rng.next_f32(), rng.next_f32()) * 2.0
             - Vector3::new(1.0, 1.0, 1.0);
     }
     return p;
 }
 
-pub fn random_in_unit_disk() -> Vector3 {
+pub fn random_in_unit_disk(rng: &mut Pcg32) -> Vector3 {
     let mut p =
-        Vector3::new(rand::random(), rand::random(), 0.0) * 2.0 - Vector3::new(1.0, 1.0, 0.0);
+        Vector3::new(rng.next_f32(), rng.next_f32(), 0.0) * 2.0 - Vector3::new(1.0, 1.0, 0.0);
     while dot(&p, &p) >= 1.0 {
-        p = Vector3::new(rand::random(), rand::random(), 0.0) * 2.0 - Vector3::new(1.0, 1.0, 0.0);
+        p = Vector3::new(rng.next_f32(), rng.next_f32(), 0.0) * 2.0 - Vector3::new(1.0, 1.0, 0.0);
     }
     return p;
 }
@@ -144,3 +180,51 @@ pub fn cross(v1: &Vector3, v2: &Vector3) -> Vector3 {
 pub fn point_at_ray(ray: &Ray, t: f32) -> Vector3 {
     return ray.origin + ray.direction * t;
 }
+
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+
+impl Aabb {
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for axis in 0..3 {
+            let (origin, direction, min, max) = match axis {
+                0 => (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+                1 => (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+                _ => (ray.origin.z, ray.direction.z, self.min.z, self.max.z),
+            };
+            let inv_d = 1.0 / direction;
+            let mut t0 = (min - origin) * inv_d;
+            let mut t1 = (max - origin) * inv_d;
+            if inv_d < 0.0 {
+                let tmp = t0;
+                t0 = t1;
+                t1 = tmp;
+            }
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max <= t_min {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+pub fn surrounding_box(box0: &Aabb, box1: &Aabb) -> Aabb {
+    let min = Vector3::new(
+        box0.min.x.min(box1.min.x),
+        box0.min.y.min(box1.min.y),
+        box0.min.z.min(box1.min.z),
+    );
+    let max = Vector3::new(
+        box0.max.x.max(box1.max.x),
+        box0.max.y.max(box1.max.y),
+        box0.max.z.max(box1.max.z),
+    );
+    return Aabb { min: min, max: max };
+}
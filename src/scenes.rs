@@ -0,0 +1,111 @@
+use math::Pcg32;
+use math::Vector3;
+use Dielectric;
+use Hittable;
+use Lambertian;
+use Metal;
+use MovingSphere;
+use Sphere;
+
+/// Builds the classic "many spheres" showcase scene: a large ground sphere,
+/// a grid of small jittered spheres with randomly chosen materials, and
+/// three large feature spheres (glass, matte, metal).
+pub fn random_scene(seed: u64) -> Vec<Box<Hittable>> {
+    let mut rng = Pcg32::new(seed, 0);
+    let mut objects: Vec<Box<Hittable>> = Vec::new();
+
+    objects.push(Box::new(Sphere {
+        position: Vector3::new(0.0, -1000.0, 0.0),
+        radius: 1000.0,
+        material: Box::new(Lambertian {
+            albedo: Vector3::new(0.5, 0.5, 0.5),
+        }),
+    }));
+
+    let feature_centers = [
+        Vector3::new(0.0, 1.0, 0.0),
+        Vector3::new(-4.0, 1.0, 0.0),
+        Vector3::new(4.0, 1.0, 0.0),
+    ];
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = Vector3::new(
+                a as f32 + 0.9 * rng.next_f32(),
+                0.2,
+                b as f32 + 0.9 * rng.next_f32(),
+            );
+
+            let overlaps_feature = feature_centers.iter().any(|feature| {
+                let feature_at_grid_height = Vector3::new(feature.x, 0.2, feature.z);
+                (center - feature_at_grid_height).length() <= 0.9
+            });
+            if overlaps_feature {
+                continue;
+            }
+
+            let choose_material = rng.next_f32();
+            if choose_material < 0.8 {
+                // Diffuse spheres bounce in place over the shutter interval,
+                // giving the showcase render its signature motion-blur streaks.
+                let center1 = center + Vector3::new(0.0, 0.5 * rng.next_f32(), 0.0);
+                objects.push(Box::new(MovingSphere {
+                    radius: 0.2,
+                    center0: center,
+                    center1: center1,
+                    time0: 0.0,
+                    time1: 1.0,
+                    material: Box::new(Lambertian {
+                        albedo: Vector3::new(
+                            rng.next_f32() * rng.next_f32(),
+                            rng.next_f32() * rng.next_f32(),
+                            rng.next_f32() * rng.next_f32(),
+                        ),
+                    }),
+                }));
+            } else if choose_material < 0.95 {
+                objects.push(Box::new(Sphere {
+                    position: center,
+                    radius: 0.2,
+                    material: Box::new(Metal {
+                        albedo: Vector3::new(
+                            0.5 * (1.0 + rng.next_f32()),
+                            0.5 * (1.0 + rng.next_f32()),
+                            0.5 * (1.0 + rng.next_f32()),
+                        ),
+                        fuzz: 0.5 * rng.next_f32(),
+                    }),
+                }));
+            } else {
+                objects.push(Box::new(Sphere {
+                    position: center,
+                    radius: 0.2,
+                    material: Box::new(Dielectric { ref_idx: 1.5 }),
+                }));
+            }
+        }
+    }
+
+    objects.push(Box::new(Sphere {
+        position: feature_centers[0],
+        radius: 1.0,
+        material: Box::new(Dielectric { ref_idx: 1.5 }),
+    }));
+    objects.push(Box::new(Sphere {
+        position: feature_centers[1],
+        radius: 1.0,
+        material: Box::new(Lambertian {
+            albedo: Vector3::new(0.4, 0.2, 0.1),
+        }),
+    }));
+    objects.push(Box::new(Sphere {
+        position: feature_centers[2],
+        radius: 1.0,
+        material: Box::new(Metal {
+            albedo: Vector3::new(0.7, 0.6, 0.5),
+            fuzz: 0.0,
+        }),
+    }));
+
+    return objects;
+}